@@ -1,23 +1,381 @@
-use std::process::exit;
+use std::{env, process::exit};
 
-use actix_web::{App, HttpServer};
+use actix_cors::Cors;
+use actix_web::{
+    App, HttpResponse, HttpServer, Responder, dev::Service, error::ErrorForbidden, get, http::Uri,
+    http::header, middleware::Compress,
+};
 use dotenvy::dotenv;
+use futures::future::{Either, ready};
 use messaging::MessagingModule;
+use tracing_subscriber::EnvFilter;
+
+#[get("/healthz")]
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().finish()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Config {
+    host: String,
+    port: u16,
+    origins: Vec<String>,
+    any_origin: bool,
+    credentials: bool,
+}
+
+fn config_from_env() -> Result<Config, String> {
+    config_from(|name| env::var(name).ok())
+}
+
+fn config_from(var: impl Fn(&str) -> Option<String>) -> Result<Config, String> {
+    let host = var("HOST").unwrap_or_else(|| "127.0.0.1".to_string());
+    let port = match var("PORT") {
+        Some(p) => p
+            .parse::<u16>()
+            .map_err(|e| format!("invalid PORT {p:?}: {e}"))?,
+        None => 8082,
+    };
+
+    let entries: Vec<String> = var("CORS_ALLOWED_ORIGINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|o| !o.is_empty())
+        .map(str::to_string)
+        .collect();
+    let any_origin = entries.iter().any(|o| o == "*");
+    let origins = entries
+        .iter()
+        .filter(|o| *o != "*")
+        .map(|o| normalize_origin(o))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let credentials = parse_credentials(var("CORS_ALLOW_CREDENTIALS").as_deref())?;
+    if any_origin && credentials {
+        return Err("CORS_ALLOW_CREDENTIALS cannot be combined with a * origin".to_string());
+    }
+
+    Ok(Config {
+        host,
+        port,
+        origins,
+        any_origin,
+        credentials,
+    })
+}
+
+/// Browsers send `Origin` as lowercase `scheme://host[:port]` with the
+/// default port left off. Entries are normalised to that form, and
+/// anything that could never match (path, query, fragment, userinfo) is
+/// rejected.
+fn normalize_origin(origin: &str) -> Result<String, String> {
+    let invalid = || format!("invalid CORS origin {origin:?}, expected scheme://host[:port]");
+    if origin.contains(['@', '#']) || origin.ends_with('/') {
+        return Err(invalid());
+    }
+    let uri = origin.parse::<Uri>().map_err(|_| invalid())?;
+    let (Some(scheme), Some(authority)) = (uri.scheme_str(), uri.authority()) else {
+        return Err(invalid());
+    };
+    if uri.path() != "/" || uri.query().is_some() {
+        return Err(invalid());
+    }
+
+    let scheme = scheme.to_ascii_lowercase();
+    let default_port = match scheme.as_str() {
+        "http" => 80,
+        "https" => 443,
+        _ => return Err(invalid()),
+    };
+    let host = authority.host().to_ascii_lowercase();
+    let has_port = authority.as_str().len() > authority.host().len();
+    match authority.port_u16() {
+        Some(port) if port != default_port => Ok(format!("{scheme}://{host}:{port}")),
+        Some(_) => Ok(format!("{scheme}://{host}")),
+        None if has_port => Err(invalid()),
+        None => Ok(format!("{scheme}://{host}")),
+    }
+}
+
+fn parse_credentials(value: Option<&str>) -> Result<bool, String> {
+    match value.unwrap_or_default().to_ascii_lowercase().as_str() {
+        "" | "false" | "0" => Ok(false),
+        "true" | "1" => Ok(true),
+        v => Err(format!(
+            "invalid CORS_ALLOW_CREDENTIALS {v:?}, expected true or false"
+        )),
+    }
+}
+
+fn cors(config: &Config) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"])
+        .allowed_headers(vec![header::AUTHORIZATION, header::CONTENT_TYPE])
+        .max_age(3600)
+        .block_on_origin_mismatch(false);
+    if config.any_origin {
+        cors = cors.allow_any_origin();
+    } else {
+        for origin in &config.origins {
+            cors = cors.allowed_origin(origin);
+        }
+    }
+    if config.credentials {
+        cors = cors.supports_credentials();
+    }
+    cors
+}
+
+/// CORS does not apply to WebSocket upgrades, so cross-site pages could
+/// otherwise open an authenticated socket. An upgrade passes when it has
+/// no `Origin` (non-browser clients), when the origin matches `Host`, or
+/// when it is on the allowlist.
+fn ws_origin_allowed(origin: Option<&str>, host: Option<&str>, config: &Config) -> bool {
+    let Some(origin) = origin else {
+        return true;
+    };
+    if config.any_origin {
+        return true;
+    }
+    let Ok(origin) = normalize_origin(origin) else {
+        return false;
+    };
+    let same_host = host.is_some_and(|host| {
+        origin
+            .split_once("://")
+            .is_some_and(|(_, authority)| authority.eq_ignore_ascii_case(host))
+    });
+    same_host || config.origins.contains(&origin)
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
+    if let Err(e) = tracing_subscriber::fmt()
+        .json()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .try_init()
+    {
+        tracing::warn!(error = %e, "global tracing subscriber already installed, keeping it");
+    }
+
+    let config = match config_from_env() {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::error!(error = %e, "invalid configuration");
+            exit(1)
+        }
+    };
+
     let mm = match MessagingModule::new().await {
         Ok(r) => r,
         Err(r) => {
-            eprintln!("Error in initializing messaging module: {}", r);
+            tracing::error!(error = %r, "failed to initialize messaging module");
             exit(1)
         }
     };
 
-    HttpServer::new(move || App::new().configure(|cfg| mm.config(cfg, "messages")))
-        .workers(12)
-        .bind(("127.0.0.1", 8082))?
-        .run()
-        .await
+    let app_config = config.clone();
+    let server = HttpServer::new(move || {
+        let ws_config = app_config.clone();
+        App::new()
+            .wrap(cors(&app_config))
+            .wrap(Compress::default())
+            .wrap_fn(move |req, srv| {
+                let headers = req.headers();
+                let upgrade = headers
+                    .get(header::UPGRADE)
+                    .and_then(|v| v.to_str().ok())
+                    .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+                if upgrade {
+                    let origin = headers
+                        .get(header::ORIGIN)
+                        .map(|v| v.to_str().unwrap_or_default());
+                    let host = headers.get(header::HOST).and_then(|v| v.to_str().ok());
+                    if !ws_origin_allowed(origin, host, &ws_config) {
+                        tracing::warn!(
+                            origin = origin.unwrap_or_default(),
+                            host = host.unwrap_or_default(),
+                            "rejected cross-origin WebSocket upgrade"
+                        );
+                        return Either::Right(ready(Err(ErrorForbidden("origin not allowed"))));
+                    }
+                }
+                Either::Left(srv.call(req))
+            })
+            .service(healthz)
+            .configure(|cfg| mm.config(cfg, "messages"))
+    })
+    .workers(12);
+
+    let server = match server.bind((config.host.as_str(), config.port)) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::error!(host = %config.host, port = config.port, error = %e, "failed to bind");
+            exit(1)
+        }
+    };
+
+    tracing::info!(addrs = ?server.addrs(), "listening");
+    server.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(vars: &[(&str, &str)]) -> Result<Config, String> {
+        config_from(|name| {
+            vars.iter()
+                .find(|(k, _)| *k == name)
+                .map(|(_, v)| v.to_string())
+        })
+    }
+
+    #[test]
+    fn normalize_origin_cases() {
+        let cases = [
+            ("https://app.example.com", Ok("https://app.example.com")),
+            ("http://localhost:3000", Ok("http://localhost:3000")),
+            ("HTTPS://App.Example.com", Ok("https://app.example.com")),
+            ("https://a.com:443", Ok("https://a.com")),
+            ("http://a.com:80", Ok("http://a.com")),
+            ("https://a.com:80", Ok("https://a.com:80")),
+            ("http://[::1]:8080", Ok("http://[::1]:8080")),
+            ("https://a.com/", Err(())),
+            ("https://a.com/app", Err(())),
+            ("https://a.com?x=1", Err(())),
+            ("https://a.com#x", Err(())),
+            ("https://u@a.com", Err(())),
+            ("https://a.com:99999", Err(())),
+            ("ftp://a.com", Err(())),
+            ("a.com", Err(())),
+            ("", Err(())),
+        ];
+        for (input, expected) in cases {
+            let got = normalize_origin(input);
+            assert_eq!(
+                got.as_deref().map_err(|_| ()),
+                expected,
+                "normalize_origin({input:?})"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_credentials_cases() {
+        let cases = [
+            (None, Ok(false)),
+            (Some(""), Ok(false)),
+            (Some("false"), Ok(false)),
+            (Some("0"), Ok(false)),
+            (Some("true"), Ok(true)),
+            (Some("TRUE"), Ok(true)),
+            (Some("1"), Ok(true)),
+            (Some("yes"), Err(())),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                parse_credentials(input).map_err(|_| ()),
+                expected,
+                "parse_credentials({input:?})"
+            );
+        }
+    }
+
+    #[test]
+    fn config_defaults() {
+        let c = config(&[]).unwrap();
+        assert_eq!(c.host, "127.0.0.1");
+        assert_eq!(c.port, 8082);
+        assert!(c.origins.is_empty());
+        assert!(!c.any_origin);
+        assert!(!c.credentials);
+    }
+
+    #[test]
+    fn config_rejects_bad_port() {
+        assert!(config(&[("PORT", "http")]).is_err());
+    }
+
+    #[test]
+    fn wildcard_wins_regardless_of_position() {
+        for origins in ["*,https://a.com", "https://a.com,*"] {
+            let c = config(&[("CORS_ALLOWED_ORIGINS", origins)]).unwrap();
+            assert!(c.any_origin, "{origins}");
+        }
+    }
+
+    #[test]
+    fn wildcard_with_credentials_is_refused() {
+        let c = config(&[
+            ("CORS_ALLOWED_ORIGINS", "https://a.com, *"),
+            ("CORS_ALLOW_CREDENTIALS", "true"),
+        ]);
+        assert!(c.is_err());
+    }
+
+    #[test]
+    fn ws_origin_cases() {
+        let config = Config {
+            origins: vec!["https://app.example.com".to_string()],
+            ..config(&[]).unwrap()
+        };
+        let cases = [
+            (None, Some("chat.example.com"), true),
+            (
+                Some("https://chat.example.com"),
+                Some("chat.example.com"),
+                true,
+            ),
+            (Some("http://127.0.0.1:8080"), Some("127.0.0.1:8080"), true),
+            (
+                Some("HTTPS://App.Example.com"),
+                Some("chat.example.com"),
+                true,
+            ),
+            (
+                Some("https://evil.example"),
+                Some("chat.example.com"),
+                false,
+            ),
+            (Some("https://evil.example"), None, false),
+            (Some("http://127.0.0.1:9000"), Some("127.0.0.1:8080"), false),
+            (Some("null"), Some("chat.example.com"), false),
+            (Some(""), Some("chat.example.com"), false),
+        ];
+        for (origin, host, expected) in cases {
+            assert_eq!(
+                ws_origin_allowed(origin, host, &config),
+                expected,
+                "ws_origin_allowed({origin:?}, {host:?})"
+            );
+        }
+
+        let any = Config {
+            any_origin: true,
+            ..config.clone()
+        };
+        assert!(ws_origin_allowed(
+            Some("https://evil.example"),
+            Some("chat.example.com"),
+            &any
+        ));
+    }
+
+    #[test]
+    fn origins_are_normalised() {
+        let c = config(&[(
+            "CORS_ALLOWED_ORIGINS",
+            "HTTPS://App.example.com:443, http://localhost:3000",
+        )])
+        .unwrap();
+        assert_eq!(
+            c.origins,
+            ["https://app.example.com", "http://localhost:3000"]
+        );
+    }
 }